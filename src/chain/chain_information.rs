@@ -21,12 +21,24 @@
 //! They also do not contain the past history of the chain. It is, however, similarly possible to
 //! for instance download the history from other nodes.
 
+pub mod cht;
+pub mod warp_sync;
+
 use crate::{finality::grandpa, header, verify::babe};
 
 use alloc::vec::Vec;
+use parity_scale_codec::{Decode, Encode};
 
 /// Information about the latest finalized block and state found in its ancestors.
-#[derive(Debug, Clone)]
+///
+/// This struct derives both [`Encode`]/[`Decode`] (SCALE) and, behind the `serde` feature,
+/// `serde::Serialize`/`serde::Deserialize`, so that it can be written to disk and reloaded. See
+/// [`ChainInformation::validate`] to check the consistency of a value obtained this way.
+#[derive(Debug, Clone, Encode, Decode)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize)
+)]
 pub struct ChainInformation {
     /// SCALE encoding of the header of the highest known finalized block.
     ///
@@ -42,6 +54,14 @@ pub struct ChainInformation {
     /// Babe epoch information about the epoch the finalized block belongs to.
     ///
     /// Must be `None` if and only if the finalized block is block #0 or belongs to epoch #0.
+    ///
+    /// > **Note**: Block #1 is always part of epoch #0, as epoch #0 starts at block #1's own
+    /// >           slot by definition. Telling whether any later block still belongs to epoch #0,
+    /// >           however, additionally requires knowing the epoch's duration, which isn't part
+    /// >           of this struct and is instead found in the genesis BABE configuration (see
+    /// >           [`ChainInformationConfig::babe_genesis_config`]).
+    /// >           [`ChainInformation::validate`] can therefore only check this invariant for
+    /// >           blocks #0 and #1.
     pub babe_finalized_block_epoch_information:
         Option<(header::BabeNextEpoch, header::BabeNextConfig)>,
 
@@ -65,6 +85,11 @@ pub struct ChainInformation {
     /// are already finalized but not triggered yet. These changes will for sure happen.
     // TODO: I believe this should be an Option
     pub grandpa_finalized_scheduled_changes: Vec<FinalizedScheduledChange>,
+
+    /// Ordered list of Canonical Hash Trie roots covering the chain's past finalized history, up
+    /// to but excluding [`ChainInformation::finalized_block_header`]. See the [`cht`] module for
+    /// more information.
+    pub cht_roots: cht::ChtRoots,
 }
 
 impl ChainInformation {
@@ -92,8 +117,120 @@ impl ChainInformation {
             grandpa_after_finalized_block_authorities_set_id: 0,
             grandpa_finalized_scheduled_changes: Vec::new(),
             grandpa_finalized_triggered_authorities: grandpa_genesis_config.initial_authorities,
+            cht_roots: cht::ChtRoots::empty(),
         })
     }
+
+    /// Checks the internal consistency of the fields of this [`ChainInformation`].
+    ///
+    /// As explained in the module-level documentation, a [`ChainInformation`] is commonly written to
+    /// disk and reloaded at a later point in time, and nothing prevents the data on disk from
+    /// having been modified or corrupted in the meantime. This method lets a caller that has
+    /// just deserialized a [`ChainInformation`] check that the invariants documented on its
+    /// fields still hold, before trusting its content.
+    ///
+    /// > **Note**: This only checks for *internal* consistency. It does in no way prove that the
+    /// >           finalized block or the information about it are actually correct.
+    ///
+    /// > **Note**: The "must be `None` if and only if (...) belongs to epoch #0" part of
+    /// >           [`ChainInformation::babe_finalized_block_epoch_information`]'s invariant can
+    /// >           only be checked here for blocks #0 and #1; telling whether any later block
+    /// >           still belongs to epoch #0 requires the epoch's duration, which is part of the
+    /// >           genesis BABE configuration rather than of this struct. See the field's own
+    /// >           documentation for more information.
+    // TODO: add a #[cfg(test)] fixture-based test (genesis, block #1, and a later non-genesis
+    // block) covering every branch above; blocked for now on `header::Header` not existing
+    // anywhere in this checkout, so there's no way to construct a fixture without guessing at a
+    // struct this module doesn't define.
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        let is_genesis = self.finalized_block_header.number == 0;
+
+        if is_genesis {
+            if self.babe_finalized_block_epoch_information.is_some() {
+                return Err(ValidationError::BabeEpochInformationAtGenesis);
+            }
+            if self.babe_finalized_next_epoch_transition.is_some() {
+                return Err(ValidationError::BabeNextEpochTransitionAtGenesis);
+            }
+            if self.babe_finalized_block1_slot_number.is_some() {
+                return Err(ValidationError::BabeBlock1SlotNumberAtGenesis);
+            }
+            if self.grandpa_after_finalized_block_authorities_set_id != 0 {
+                return Err(ValidationError::NonZeroGrandpaSetIdAtGenesis);
+            }
+        } else {
+            if self.babe_finalized_next_epoch_transition.is_none() {
+                return Err(ValidationError::MissingBabeNextEpochTransition);
+            }
+            if self.babe_finalized_block1_slot_number.is_none() {
+                return Err(ValidationError::MissingBabeBlock1SlotNumber);
+            }
+
+            // Block #1 is always part of epoch #0, regardless of the epoch's duration (which
+            // this struct doesn't even know), since epoch #0 starts at block #1's own slot by
+            // definition. `babe_finalized_block_epoch_information` being `Some` here would
+            // therefore be incoherent.
+            //
+            // Note that this is as far as this check can go: telling whether a block *after*
+            // block #1 still belongs to epoch #0 requires knowing the epoch's duration, which
+            // lives in the genesis BABE configuration alongside, not inside, this struct.
+            if self.finalized_block_header.number == 1
+                && self.babe_finalized_block_epoch_information.is_some()
+            {
+                return Err(ValidationError::BabeEpochInformationAtBlockOne);
+            }
+        }
+
+        for change in &self.grandpa_finalized_scheduled_changes {
+            if change.trigger_block_height <= self.finalized_block_header.number {
+                return Err(ValidationError::ScheduledChangeBeforeFinalizedBlock {
+                    trigger_block_height: change.trigger_block_height,
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Error potentially returned by [`ChainInformation::validate`].
+#[derive(Debug, derive_more::Display)]
+pub enum ValidationError {
+    /// [`ChainInformation::babe_finalized_block_epoch_information`] is `Some` even though the
+    /// finalized block is the genesis block.
+    #[display(fmt = "babe_finalized_block_epoch_information is Some at the genesis block")]
+    BabeEpochInformationAtGenesis,
+    /// [`ChainInformation::babe_finalized_block_epoch_information`] is `Some` even though the
+    /// finalized block is block #1, which always belongs to epoch #0.
+    #[display(fmt = "babe_finalized_block_epoch_information is Some at block #1")]
+    BabeEpochInformationAtBlockOne,
+    /// [`ChainInformation::babe_finalized_next_epoch_transition`] is `None` even though the
+    /// finalized block isn't the genesis block.
+    #[display(fmt = "babe_finalized_next_epoch_transition is missing for a non-genesis block")]
+    MissingBabeNextEpochTransition,
+    /// [`ChainInformation::babe_finalized_next_epoch_transition`] is `Some` even though the
+    /// finalized block is the genesis block.
+    #[display(fmt = "babe_finalized_next_epoch_transition is Some at the genesis block")]
+    BabeNextEpochTransitionAtGenesis,
+    /// [`ChainInformation::babe_finalized_block1_slot_number`] is `None` even though the
+    /// finalized block isn't the genesis block.
+    #[display(fmt = "babe_finalized_block1_slot_number is missing for a non-genesis block")]
+    MissingBabeBlock1SlotNumber,
+    /// [`ChainInformation::babe_finalized_block1_slot_number`] is `Some` even though the
+    /// finalized block is the genesis block.
+    #[display(fmt = "babe_finalized_block1_slot_number is Some at the genesis block")]
+    BabeBlock1SlotNumberAtGenesis,
+    /// [`ChainInformation::grandpa_after_finalized_block_authorities_set_id`] isn't `0` even
+    /// though the finalized block is the genesis block.
+    #[display(fmt = "grandpa_after_finalized_block_authorities_set_id is non-zero at the genesis block")]
+    NonZeroGrandpaSetIdAtGenesis,
+    /// An entry of [`ChainInformation::grandpa_finalized_scheduled_changes`] is scheduled to
+    /// trigger at or before the finalized block.
+    #[display(
+        fmt = "GrandPa scheduled change trigger height ({}) isn't after the finalized block",
+        trigger_block_height
+    )]
+    ScheduledChangeBeforeFinalizedBlock { trigger_block_height: u64 },
 }
 
 impl<'a> From<ChainInformationRef<'a>> for ChainInformation {
@@ -113,6 +250,7 @@ impl<'a> From<ChainInformationRef<'a>> for ChainInformation {
                 .grandpa_finalized_triggered_authorities
                 .into(),
             grandpa_finalized_scheduled_changes: info.grandpa_finalized_scheduled_changes.into(),
+            cht_roots: info.cht_roots.clone(),
         }
     }
 }
@@ -124,7 +262,11 @@ pub enum FromGenesisStorageError {
     GrandpaConfigLoad(grandpa::chain_config::FromGenesisStorageError),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Encode, Decode)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize)
+)]
 pub struct FinalizedScheduledChange {
     pub trigger_block_height: u64,
     pub new_authorities_list: Vec<header::GrandpaAuthority>,
@@ -156,6 +298,9 @@ pub struct ChainInformationRef<'a> {
     /// See equivalent field in [`ChanInformation`].
     // TODO: better type, as a Vec is not in the spirit of this struct; however it's likely that this "scheduled changes" field will disappear altogether
     pub grandpa_finalized_scheduled_changes: Vec<FinalizedScheduledChange>,
+
+    /// See equivalent field in [`ChainInformation`].
+    pub cht_roots: &'a cht::ChtRoots,
 }
 
 impl<'a> From<&'a ChainInformation> for ChainInformationRef<'a> {
@@ -175,6 +320,7 @@ impl<'a> From<&'a ChainInformation> for ChainInformationRef<'a> {
                 .grandpa_after_finalized_block_authorities_set_id,
             grandpa_finalized_triggered_authorities: &info.grandpa_finalized_triggered_authorities,
             grandpa_finalized_scheduled_changes: info.grandpa_finalized_scheduled_changes.clone(),
+            cht_roots: &info.cht_roots,
         }
     }
 }
@@ -0,0 +1,220 @@
+//! Canonical Hash Trie (CHT) roots.
+//!
+//! Since [`ChainInformation`](super::ChainInformation) deliberately doesn't keep track of the
+//! chain's past history, a light client that only holds a [`ChainInformation`](super::ChainInformation)
+//! has no way to later prove that some old block hash is part of the canonical chain.
+//!
+//! This module fixes that by grouping finalized headers into fixed-size ranges and committing to
+//! each range with a single Merkle root, matching the scheme historically used by Substrate under
+//! the name "Canonical Hash Trie". [`ChtRoots`] is meant to be kept alongside a
+//! [`ChainInformation`](super::ChainInformation): every time [`CHT_SIZE`] more blocks have been
+//! finalized, [`build_cht_root`] is used to compute the next root, which is then appended to the
+//! list. A light client can later request any ancestor header from a full node and use
+//! [`verify_header_in_cht`] to check it against the root it stored, without having to keep the
+//! header itself around.
+
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use parity_scale_codec::{Decode, Encode};
+
+/// Number of blocks grouped together into a single CHT, matching Substrate's own constant.
+pub const CHT_SIZE: u64 = 2048;
+
+/// Root of the Merkle tree built over a range of [`CHT_SIZE`] `(block_number, block_hash)` pairs.
+pub type Root = [u8; 32];
+
+/// Ordered list of [`Root`]s, one per completed range of [`CHT_SIZE`] blocks, starting with the
+/// range covering blocks `1..=CHT_SIZE`. Block #0, the genesis, is never part of a CHT, as a
+/// [`ChainInformation`](super::ChainInformation) always keeps track of it directly.
+#[derive(Debug, Clone, Default, Encode, Decode)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct ChtRoots {
+    roots: Vec<Root>,
+}
+
+impl ChtRoots {
+    /// Creates an empty list of CHT roots, to use alongside a
+    /// [`ChainInformation`](super::ChainInformation) that doesn't have any history yet (e.g. one
+    /// obtained through [`ChainInformation::from_genesis_storage`](super::ChainInformation::from_genesis_storage)).
+    pub fn empty() -> ChtRoots {
+        ChtRoots { roots: Vec::new() }
+    }
+
+    /// Appends the [`Root`] of the range that immediately follows the last one that was pushed
+    /// (or the first range, if this is the first call).
+    pub fn push(&mut self, root: Root) {
+        self.roots.push(root);
+    }
+
+    /// Number of completed CHT ranges so far.
+    pub fn len(&self) -> usize {
+        self.roots.len()
+    }
+
+    /// Returns the [`Root`] of the CHT range containing `number`, or `None` if that range hasn't
+    /// been completed yet (or `number` is the genesis block, which is never part of a CHT).
+    pub fn cht_root_for_block(&self, number: u64) -> Option<&Root> {
+        let range_index = number.checked_sub(1)? / CHT_SIZE;
+        self.roots.get(usize::try_from(range_index).ok()?)
+    }
+}
+
+/// Builds the [`Root`] of the CHT covering `CHT_SIZE` consecutive blocks, given, in increasing
+/// block number order, the hash of each of these blocks.
+///
+/// # Panics
+///
+/// Panics if `leaves` doesn't yield exactly [`CHT_SIZE`] items.
+///
+pub fn build_cht_root(leaves: impl ExactSizeIterator<Item = [u8; 32]>) -> Root {
+    assert_eq!(leaves.len(), usize::try_from(CHT_SIZE).unwrap());
+
+    let mut level = leaves.collect::<Vec<_>>();
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [a, b] => hash_pair(a, b),
+                [a] => hash_pair(a, a),
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+    level[0]
+}
+
+/// Merkle proof that a `(block_number, block_hash)` pair is part of the CHT committed to by a
+/// [`Root`], as returned by [`verify_header_in_cht`]'s caller after requesting it from a full
+/// node.
+#[derive(Debug, Clone)]
+pub struct Proof {
+    /// Hash of each of the sibling nodes met on the path from the leaf up to the root, in that
+    /// order.
+    pub siblings: Vec<[u8; 32]>,
+}
+
+/// Verifies that, according to `proof`, `header_hash` is the hash of block number `number` that
+/// `root` commits to.
+pub fn verify_header_in_cht(
+    root: &Root,
+    number: u64,
+    header_hash: &[u8; 32],
+    proof: &Proof,
+) -> bool {
+    let leaf_index = match number.checked_sub(1) {
+        Some(n) => n,
+        None => return false,
+    };
+
+    let mut index = leaf_index % CHT_SIZE;
+    let mut current = *header_hash;
+
+    for sibling in &proof.siblings {
+        current = if index % 2 == 0 {
+            hash_pair(&current, sibling)
+        } else {
+            hash_pair(sibling, &current)
+        };
+        index /= 2;
+    }
+
+    current == *root
+}
+
+/// Hashes together two nodes of the Merkle tree built by [`build_cht_root`].
+fn hash_pair(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+    use sha2::Digest as _;
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(a);
+    hasher.update(b);
+    <[u8; 32]>::from(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(i: u64) -> [u8; 32] {
+        let mut out = [0; 32];
+        out[..8].copy_from_slice(&i.to_le_bytes());
+        out
+    }
+
+    /// Reference implementation of the proof that [`build_cht_root`] implicitly commits to for
+    /// `leaves[index]`, built by mirroring the exact same pairwise-hashing, odd-leaf-duplication
+    /// logic level by level.
+    fn build_proof(leaves: &[[u8; 32]], mut index: usize) -> Proof {
+        let mut level = leaves.to_vec();
+        let mut siblings = Vec::new();
+
+        while level.len() > 1 {
+            let sibling_index = if index % 2 == 0 { index + 1 } else { index - 1 };
+            siblings.push(*level.get(sibling_index).unwrap_or(&level[index]));
+
+            level = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [a, b] => hash_pair(a, b),
+                    [a] => hash_pair(a, a),
+                    _ => unreachable!(),
+                })
+                .collect();
+            index /= 2;
+        }
+
+        Proof { siblings }
+    }
+
+    #[test]
+    fn build_and_verify_round_trip() {
+        let leaves: Vec<_> = (0..CHT_SIZE).map(leaf).collect();
+        let root = build_cht_root(leaves.iter().copied());
+
+        let number = 1234;
+        let leaf_index = usize::try_from(number - 1).unwrap();
+        let proof = build_proof(&leaves, leaf_index);
+
+        assert!(verify_header_in_cht(
+            &root,
+            number,
+            &leaves[leaf_index],
+            &proof
+        ));
+    }
+
+    #[test]
+    fn tampered_proof_is_rejected() {
+        let leaves: Vec<_> = (0..CHT_SIZE).map(leaf).collect();
+        let root = build_cht_root(leaves.iter().copied());
+
+        let number = 42;
+        let leaf_index = usize::try_from(number - 1).unwrap();
+        let mut proof = build_proof(&leaves, leaf_index);
+        proof.siblings[0][0] ^= 0xff;
+
+        assert!(!verify_header_in_cht(
+            &root,
+            number,
+            &leaves[leaf_index],
+            &proof
+        ));
+    }
+
+    #[test]
+    fn cht_roots_tracks_completed_ranges() {
+        let mut roots = ChtRoots::empty();
+        assert_eq!(roots.cht_root_for_block(0), None); // genesis is never part of a CHT
+        assert_eq!(roots.cht_root_for_block(1), None); // first range not completed yet
+
+        let first_root = build_cht_root((0..CHT_SIZE).map(leaf).collect::<Vec<_>>().into_iter());
+        roots.push(first_root);
+
+        assert_eq!(roots.len(), 1);
+        assert_eq!(roots.cht_root_for_block(1), Some(&first_root));
+        assert_eq!(roots.cht_root_for_block(CHT_SIZE), Some(&first_root));
+        assert_eq!(roots.cht_root_for_block(CHT_SIZE + 1), None);
+    }
+}